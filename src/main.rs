@@ -1,8 +1,10 @@
 use ::std::{
-    process::{Command, Child},
-    sync::mpsc::channel,
-    time::Duration,
-    ffi::{OsString, OsStr}
+    process::{Command, Child, Stdio},
+    sync::{mpsc::{channel, Receiver, RecvTimeoutError}, Arc, atomic::{AtomicBool, Ordering}},
+    time::{Duration, Instant},
+    ffi::{OsString, OsStr},
+    io::BufReader,
+    path::PathBuf
 };
 
 use ::notify::{
@@ -15,8 +17,9 @@ use ::clap::{
     Parser,
     AppSettings
 };
-use ::ignore::gitignore::GitignoreBuilder;
+use ::ignore::gitignore::{Gitignore, GitignoreBuilder};
 use ::strum::{IntoStaticStr, EnumString, EnumVariantNames, VariantNames};
+use ::cargo_metadata::{Message, MetadataCommand};
 
 #[derive(Clone, Copy, EnumString, EnumVariantNames, IntoStaticStr, Debug)]
 #[strum(serialize_all = "lowercase")]
@@ -33,8 +36,10 @@ struct BuildNRun {
     watch: Vec<OsString>,
     #[clap(long, short, help = "No output printed to stdout")]
     quiet: bool,
-    #[clap(long, parse(from_os_str), value_name = "NAME", help = "Build only the specified binary")]
-    bin: OsString,
+    #[clap(long, parse(from_os_str), value_name = "NAME", conflicts_with = "example", help = "Build only the specified binary")]
+    bin: Option<OsString>,
+    #[clap(long, parse(from_os_str), value_name = "NAME", conflicts_with = "bin", help = "Build only the specified example")]
+    example: Option<OsString>,
     #[clap(long, short, value_name = "SPEC", help = "Package with the target to run")]
     package: Option<OsString>,
     #[clap(long, short, value_name = "N", help = "Number of parallel jobs, default to # of CPUs")]
@@ -67,136 +72,412 @@ struct BuildNRun {
     locked: bool,
     #[clap(long, help = "Run without accessing the network")]
     offline: bool,
+    #[clap(long, value_name = "NAME", default_value = "SIGTERM", help = "Signal to send the running process before escalating to SIGKILL")]
+    signal: String,
+    #[clap(long, value_name = "MS", default_value = "5000", help = "Milliseconds to wait for graceful shutdown before sending SIGKILL")]
+    restart_timeout: u64,
+    #[clap(short = 'x', long = "exec", parse(from_os_str), value_name = "CARGO-SUBCOMMAND", help = "Cargo subcommand to run instead of build+run, may be repeated")]
+    exec: Vec<OsString>,
+    #[clap(short = 's', long = "shell", parse(from_os_str), value_name = "CMD", help = "Shell command to run instead of build+run, may be repeated")]
+    shell: Vec<OsString>,
+    #[clap(long, value_name = "MS", default_value = "250", help = "Filesystem event debounce window")]
+    debounce: u64,
+    #[clap(long, value_name = "MS", default_value = "0", help = "Quiet period after the last change before rebuilding, on top of --debounce")]
+    delay: u64,
+    #[clap(long, help = "Clear the terminal before each build")]
+    clear: bool,
+    #[clap(long, help = "Skip the initial build and wait for the first change")]
+    postpone: bool,
+    #[clap(long, parse(from_os_str), value_name = "GLOB", help = "Extra glob pattern to ignore, on top of the gitignore files; may be repeated")]
+    ignore: Vec<OsString>,
     #[clap(parse(from_os_str))]
     args: Vec<OsString>
 }
 
-fn build(bnr: &BuildNRun) -> bool {
-    let mut args = vec![OsStr::new("build")];
+/// The runnable target selected by `--bin`/`--example`, or inferred when the
+/// package has exactly one binary.
+enum Target {
+    Bin(String),
+    Example(String)
+}
 
-    if bnr.quiet {
-        args.push(OsStr::new("--quiet"));
+impl Target {
+    fn select_flag(&self) -> OsString {
+        let (flag, name) = match self {
+            Target::Bin(name) => ("--bin=", name),
+            Target::Example(name) => ("--example=", name)
+        };
+        let mut flag = OsStr::new(flag).to_owned();
+        flag.push(name);
+        flag
     }
 
-    let mut bin_str = OsStr::new("--bin=").to_owned();
-    bin_str.push(&bnr.bin);
-    args.push(&bin_str);
+    fn kind(&self) -> &'static str {
+        match self {
+            Target::Bin(_) => "bin",
+            Target::Example(_) => "example"
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Target::Bin(name) | Target::Example(name) => name
+        }
+    }
+}
+
+/// Pick the target to build and run. An explicit `--bin`/`--example` is used
+/// as given, without checking it against the package. When neither is given,
+/// the root package's binaries are read from `cargo metadata`: its single
+/// binary is chosen, and otherwise the program exits with an error listing
+/// the available binaries (or, when there are none, the examples).
+fn resolve_target(bnr: &BuildNRun) -> Target {
+    if let Some(bin) = &bnr.bin {
+        return Target::Bin(bin.to_string_lossy().into_owned());
+    }
+    if let Some(example) = &bnr.example {
+        return Target::Example(example.to_string_lossy().into_owned());
+    }
+
+    let mut cmd = MetadataCommand::new();
+    if let Some(mft) = &bnr.manifest_path {
+        cmd.manifest_path(mft);
+    }
+    let metadata = cmd.no_deps().exec().expect("`cargo metadata` failed");
+    let package = metadata.root_package().expect("no root package; select a target with --bin or --example");
+
+    let bins: Vec<&str> = package.targets.iter()
+        .filter(|t| t.kind.iter().any(|k| k == "bin"))
+        .map(|t| t.name.as_str())
+        .collect();
+
+    match bins.as_slice() {
+        [name] => Target::Bin(name.to_string()),
+        [] => {
+            let examples: Vec<&str> = package.targets.iter()
+                .filter(|t| t.kind.iter().any(|k| k == "example"))
+                .map(|t| t.name.as_str())
+                .collect();
+            eprintln!("error: no binaries in `{}`; available examples via --example: {}", package.name, examples.join(", "));
+            ::std::process::exit(1);
+        }
+        many => {
+            eprintln!("error: `{}` has multiple binaries, choose one with --bin: {}", package.name, many.join(", "));
+            ::std::process::exit(1);
+        }
+    }
+}
+
+/// The `--release`/`--features`/`--target`/etc. selection flags, shared
+/// between `cargo build` and each `-x` step so a watch run stays consistent
+/// no matter which cargo subcommand is actually doing the work.
+fn common_args(bnr: &BuildNRun) -> Vec<OsString> {
+    let mut args = Vec::new();
+
+    if bnr.quiet {
+        args.push(OsString::from("--quiet"));
+    }
 
-    let mut package_str;
     if let Some(package) = &bnr.package {
-        package_str = OsStr::new("--package=").to_owned();
+        let mut package_str = OsStr::new("--package=").to_owned();
         package_str.push(package);
-        args.push(&package_str);
+        args.push(package_str);
     }
 
-    let jobs_str;
     if let Some(jobs) = bnr.jobs {
-        jobs_str = format!("{}", jobs);
-        args.push(OsStr::new("--jobs"));
-        args.push(OsStr::new(&jobs_str));
+        args.push(OsString::from("--jobs"));
+        args.push(OsString::from(format!("{}", jobs)));
     }
 
     if bnr.release {
-        args.push(OsStr::new("--release"));
+        args.push(OsString::from("--release"));
     }
 
-    let mut profile_str;
     if let Some(profile) = &bnr.profile {
-        profile_str = OsStr::new("--profile=").to_owned();
+        let mut profile_str = OsStr::new("--profile=").to_owned();
         profile_str.push(profile);
-        args.push(&profile_str);
+        args.push(profile_str);
     }
 
-    let mut features_strs = Vec::new();
     for features in &bnr.features {
         let mut features_str = OsStr::new("--features=").to_owned();
         features_str.push(features);
-        features_strs.push(features_str);
-    }
-    for features_str in &features_strs {
         args.push(features_str);
     }
 
     if bnr.all_features {
-        args.push(OsStr::new("--all-features"));
+        args.push(OsString::from("--all-features"));
     }
 
     if bnr.no_default_features {
-        args.push(OsStr::new("--no-default-features"));
+        args.push(OsString::from("--no-default-features"));
     }
 
-    let mut target_str;
     if let Some(target) = &bnr.target {
-        target_str = OsStr::new("--target=").to_owned();
+        let mut target_str = OsStr::new("--target=").to_owned();
         target_str.push(target);
-        args.push(&target_str);
+        args.push(target_str);
+    }
+
+    if let Some(target_dir) = &bnr.target_dir {
+        let mut target_dir_str = OsStr::new("--target-dir=").to_owned();
+        target_dir_str.push(target_dir);
+        args.push(target_dir_str);
     }
 
-    let mut manifest_path_str;
     if let Some(mft) = &bnr.manifest_path {
-        manifest_path_str = OsStr::new("--manifest-path=").to_owned();
+        let mut manifest_path_str = OsStr::new("--manifest-path=").to_owned();
         manifest_path_str.push(mft);
-        args.push(&manifest_path_str);
+        args.push(manifest_path_str);
     }
 
-    let mut message_format_path_strs = Vec::new();
     for path in &bnr.message_format_path {
         let mut message_format_path_str = OsStr::new("--message-format-path=").to_owned();
         message_format_path_str.push(path);
-        message_format_path_strs.push(message_format_path_str);
-    }
-    for message_format_path_str in &message_format_path_strs {
         args.push(message_format_path_str);
     }
 
     for _ in 0..bnr.verbose {
-        args.push(OsStr::new("-v"));
+        args.push(OsString::from("-v"));
     }
 
-    let mut color_str;
     if let Some(color) = bnr.color {
-        color_str = OsStr::new("--color=").to_owned();
+        let mut color_str = OsStr::new("--color=").to_owned();
         color_str.push(OsStr::new::<str>(color.into()));
-        args.push(&color_str);
+        args.push(color_str);
     }
 
     if bnr.frozen {
-        args.push(OsStr::new("--frozen"));
+        args.push(OsString::from("--frozen"));
     }
 
     if bnr.locked {
-        args.push(OsStr::new("--locked"));
+        args.push(OsString::from("--locked"));
     }
 
     if bnr.offline {
-        args.push(OsStr::new("--offline"));
+        args.push(OsString::from("--offline"));
     }
 
-    Command::new("cargo").args(&args).status().unwrap().success()
+    args
 }
 
-fn run(bnr: &BuildNRun) -> Option<Child> {
-    let mut exe = match &bnr.target_dir {
-        Some(td) => td.clone(),
-        None => OsString::from("target/")
-    };
-    if bnr.release {
-        exe.push("release");
-    } else {
-        exe.push("debug");
+fn build(bnr: &BuildNRun, target: &Target, last_exe: &Option<PathBuf>) -> Option<PathBuf> {
+    let mut args = vec![OsString::from("build"), OsString::from("--message-format=json-render-diagnostics")];
+
+    args.push(target.select_flag());
+    args.extend(common_args(bnr));
+
+    let mut child = Command::new("cargo")
+        .args(&args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let reader = BufReader::new(child.stdout.take().unwrap());
+    let mut executable = None;
+    for message in Message::parse_stream(reader) {
+        match message.unwrap() {
+            Message::CompilerArtifact(artifact) => {
+                if artifact.target.kind.iter().any(|k| k == target.kind()) && artifact.target.name == target.name() {
+                    if let Some(exe) = artifact.executable {
+                        executable = Some(exe.into());
+                    }
+                }
+            }
+            Message::CompilerMessage(msg) => {
+                if let Some(rendered) = msg.message.rendered {
+                    print!("{}", rendered);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !child.wait().unwrap().success() {
+        return None;
     }
-    exe.push("/");
-    exe.push(&bnr.bin);
+
+    // A build that changes nothing emits no bin artifacts; keep running the
+    // executable we already know about rather than treating that as failure.
+    executable.or_else(|| last_exe.clone())
+}
+
+fn run(bnr: &BuildNRun, exe: &PathBuf) -> Option<Child> {
     Command::new(exe).args(&bnr.args).spawn().ok()
 }
 
+/// Ask the child to shut down (rather than killing it outright), giving it
+/// `timeout` to exit on its own before escalating to SIGKILL.
+#[cfg(unix)]
+fn terminate_child(child: &mut Child, signal_name: &str, timeout: Duration) {
+    use ::nix::sys::signal::{kill, Signal};
+    use ::nix::unistd::Pid;
+    use ::std::str::FromStr;
+
+    let pid = Pid::from_raw(child.id() as i32);
+    let signalled = Signal::from_str(signal_name).ok().and_then(|sig| kill(pid, sig).ok());
+
+    if signalled.is_some() {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match child.try_wait() {
+                Ok(Some(_)) => return,
+                Ok(None) => ::std::thread::sleep(Duration::from_millis(50)),
+                Err(_) => return
+            }
+        }
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+#[cfg(windows)]
+fn terminate_child(child: &mut Child, _signal_name: &str, timeout: Duration) {
+    use ::windows_sys::Win32::System::Console::{GenerateConsoleCtrlEvent, CTRL_BREAK_EVENT};
+
+    unsafe { GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, child.id()); }
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match child.try_wait() {
+            Ok(Some(_)) => return,
+            Ok(None) => ::std::thread::sleep(Duration::from_millis(50)),
+            Err(_) => return
+        }
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+enum Step {
+    Cargo(OsString),
+    Shell(OsString)
+}
+
+/// The `-x`/`-s` steps to run on each change: `-x` steps in the order given,
+/// followed by `-s` steps in the order given. Empty falls back to the
+/// default build+run.
+fn steps(bnr: &BuildNRun) -> Vec<Step> {
+    let mut steps = Vec::new();
+    steps.extend(bnr.exec.iter().cloned().map(Step::Cargo));
+    steps.extend(bnr.shell.iter().cloned().map(Step::Shell));
+    steps
+}
+
+/// Run each step in sequence, stopping at (and reporting) the first failure.
+fn run_steps(bnr: &BuildNRun, steps: &[Step]) -> bool {
+    let common = common_args(bnr);
+
+    for step in steps {
+        let success = match step {
+            Step::Cargo(subcommand) => {
+                let mut args: Vec<OsString> = subcommand.to_string_lossy()
+                    .split_whitespace()
+                    .map(OsString::from)
+                    .collect();
+                args.extend(common.iter().cloned());
+                Command::new("cargo").args(&args).status().unwrap().success()
+            }
+            Step::Shell(cmd) => {
+                if cfg!(windows) {
+                    Command::new("cmd").args(&[OsStr::new("/C"), cmd]).status().unwrap().success()
+                } else {
+                    Command::new("sh").args(&[OsStr::new("-c"), cmd]).status().unwrap().success()
+                }
+            }
+        };
+
+        if !success {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Whether a filesystem event is one that should trigger a rebuild, i.e. it
+/// isn't gitignored (or otherwise excluded).
+fn relevant(event: &DebouncedEvent, gi: &Gitignore) -> bool {
+    match event {
+        DebouncedEvent::Write(f) => !gi.matched(f, false).is_ignore(),
+        DebouncedEvent::Rename(_, f) => !gi.matched(f, false).is_ignore(),
+        DebouncedEvent::Remove(f) => !gi.matched(f, false).is_ignore(),
+        DebouncedEvent::Create(f) => !gi.matched(f, false).is_ignore(),
+        DebouncedEvent::Rescan => true,
+        _ => false
+    }
+}
+
+/// Drain whatever's already sitting in the channel without blocking,
+/// reporting whether any of it was relevant.
+fn drain_relevant(rx: &Receiver<DebouncedEvent>, gi: &Gitignore) -> bool {
+    let mut dirty = false;
+    while let Ok(event) = rx.try_recv() {
+        if relevant(&event, gi) {
+            dirty = true;
+        }
+    }
+    dirty
+}
+
+/// Block until there's something worth rebuilding for, then coalesce any
+/// further changes that keep arriving into a single quiet period so a burst
+/// of saves collapses into one rebuild. Returns `false` if interrupted or the
+/// watcher died.
+fn wait_for_batch(rx: &Receiver<DebouncedEvent>, gi: &Gitignore, interrupted: &AtomicBool, delay: Duration) -> bool {
+    loop {
+        if interrupted.load(Ordering::SeqCst) {
+            return false;
+        }
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(event) if relevant(&event, gi) => break,
+            Ok(_) => continue,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return false
+        }
+    }
+
+    let mut quiet_since = Instant::now();
+    let poll = Duration::from_millis(50).min(delay);
+    while quiet_since.elapsed() < delay {
+        if interrupted.load(Ordering::SeqCst) {
+            return false;
+        }
+        match rx.recv_timeout(poll) {
+            Ok(event) if relevant(&event, gi) => quiet_since = Instant::now(),
+            Ok(_) => {}
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return false
+        }
+    }
+
+    true
+}
+
+fn clear_screen() {
+    print!("\x1Bc");
+}
+
 fn main() {
     let build_n_run = BuildNRun::parse();
+    let steps = steps(&build_n_run);
+    // Only the default build+run path needs a runnable target; -x/-s steps
+    // may not touch a binary at all.
+    let target = if steps.is_empty() { Some(resolve_target(&build_n_run)) } else { None };
+    let restart_timeout = Duration::from_millis(build_n_run.restart_timeout);
+    let delay = Duration::from_millis(build_n_run.delay);
 
-    let (gi, _) = GitignoreBuilder::new(".").build_global();
+    let mut gi_builder = GitignoreBuilder::new(".");
+    for pattern in &build_n_run.ignore {
+        gi_builder.add_line(None, &pattern.to_string_lossy()).expect("invalid --ignore glob");
+    }
+    let (gi, _) = gi_builder.build_global();
 
     let (tx, rx) = channel();
-    let mut watcher = watcher(tx, Duration::from_millis(250)).unwrap();
+    let mut watcher = watcher(tx, Duration::from_millis(build_n_run.debounce)).unwrap();
     if build_n_run.watch.is_empty() {
         watcher.watch(".", RecursiveMode::Recursive).unwrap();
     } else {
@@ -205,27 +486,52 @@ fn main() {
         }
     }
 
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = interrupted.clone();
+        ::ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst))
+            .expect("failed to install Ctrl-C handler");
+    }
+
     let mut proc: Option<Child> = None;
+    let mut last_exe: Option<PathBuf> = None;
+    let mut pending_build = !build_n_run.postpone;
 
-    loop {
-        if build(&build_n_run) {
-            if let Some(mut child) = proc.take() {
-                let _ = child.kill();
-                let _ = child.wait();
+    'watch: loop {
+        if pending_build {
+            if build_n_run.clear {
+                clear_screen();
             }
-            proc = run(&build_n_run);
+
+            if let Some(target) = &target {
+                if let Some(exe) = build(&build_n_run, target, &last_exe) {
+                    if let Some(mut child) = proc.take() {
+                        terminate_child(&mut child, &build_n_run.signal, restart_timeout);
+                    }
+                    proc = run(&build_n_run, &exe);
+                    last_exe = Some(exe);
+                }
+            } else {
+                run_steps(&build_n_run, &steps);
+            }
+        }
+        pending_build = true;
+
+        if interrupted.load(Ordering::SeqCst) {
+            break 'watch;
         }
 
-        loop {
-            match rx.recv().unwrap() {
-                DebouncedEvent::Write(f) if !gi.matched(&f, false).is_ignore() => break,
-                DebouncedEvent::Rename(_, f) if !gi.matched(&f, false).is_ignore() => break,
-                DebouncedEvent::Remove(f) if !gi.matched(&f, false).is_ignore() => break,
-                DebouncedEvent::Create(f) if !gi.matched(&f, false).is_ignore() => break,
-                DebouncedEvent::Rescan => break,
-               _ => continue
+        // Changes that piled up while we were busy building/running aren't
+        // dropped: we just go straight back around for another rebuild.
+        if !drain_relevant(&rx, &gi) {
+            if !wait_for_batch(&rx, &gi, &interrupted, delay) {
+                break 'watch;
             }
+            drain_relevant(&rx, &gi);
         }
-        while rx.try_recv().is_ok() {}
+    }
+
+    if let Some(mut child) = proc.take() {
+        terminate_child(&mut child, &build_n_run.signal, restart_timeout);
     }
 }